@@ -1,6 +1,276 @@
 #![no_std]
 
+use heapless::consts::U16;
+#[cfg(feature = "crypto")]
 use heapless::consts::U32;
+use heapless::ArrayLength;
+
+pub mod session;
+
+#[cfg(feature = "crypto")]
+pub mod crypto;
+
+/// Highest wire protocol version understood by this build.
+pub const PROTO_VERSION: u16 = 3;
+
+/// All wire protocol versions this build can speak, oldest first. Version 1 is
+/// the original command set; version 2 adds [`Command::Ack`] and the handshake;
+/// version 3 adds the [`Command::Telemetry`] and [`Command::ImuDump`] payloads.
+pub const SUPPORTED_PROTOCOLS: &[u16] = &[1, 2, 3];
+
+/// Pick the highest protocol version supported by both ends, given the version
+/// the peer advertises in its [`Command::Hello`]. Returns `None` if there is no
+/// common version and the peers cannot interoperate.
+pub fn negotiate(peer_proto: u16) -> Option<u16> {
+    SUPPORTED_PROTOCOLS
+        .iter()
+        .copied()
+        .filter(|&v| v <= peer_proto)
+        .max()
+}
+
+/// Errors produced while (de)serializing the protocol. These replace the bare
+/// `()` that every fallible function used to return, so a caller can tell a
+/// truncated frame from an unknown command id from a SLIP framing failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtoError {
+    /// Ran out of input bytes while a field was still expected.
+    UnexpectedEof,
+    /// Command id byte does not map to any known command in this protocol version.
+    UnknownCommand(u8),
+    /// Motor id byte does not map to any known motor position.
+    UnknownMotor(u8),
+    /// The destination buffer is full.
+    BufferFull,
+    /// SLIP framing (encode or decode) failed.
+    Framing,
+    /// Bytes remained after a complete message was decoded.
+    TrailingBytes,
+    /// A VarInt did not terminate within the 5 bytes a `u32` allows.
+    VarIntOverflow,
+}
+
+/// Write `value` as an LEB128-style VarInt (7 data bits per byte, MSB set while
+/// more bytes follow) to the end of `out`.
+pub fn write_varint<N: ArrayLength<u8>>(
+    mut value: u32,
+    out: &mut heapless::Vec<u8, N>,
+) -> Result<(), ProtoError> {
+    loop {
+        let mut byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value != 0 {
+            byte |= 0x80;
+        }
+        out.push(byte).map_err(|_| ProtoError::BufferFull)?;
+        if value == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Read an LEB128-style VarInt from `iter`. Rejects encodings longer than the
+/// 5 bytes a `u32` can need.
+pub fn read_varint<I: Iterator<Item = u8>>(iter: &mut I) -> Result<u32, ProtoError> {
+    let mut result: u32 = 0;
+    for i in 0..5 {
+        let byte = iter.next().ok_or(ProtoError::UnexpectedEof)?;
+        // The final byte only has room for the top 4 bits of a u32; any higher
+        // bits make the encoding non-canonical and must be rejected.
+        if i == 4 && byte & 0xF0 != 0 {
+            return Err(ProtoError::VarIntOverflow);
+        }
+        result |= ((byte & 0x7F) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(ProtoError::VarIntOverflow)
+}
+
+/// Wire (de)serialization for a single protocol value.
+///
+/// `decode` returns how many bytes it consumed so composite payloads parse
+/// compositionally, and `encode` appends to `out` for the same reason. Users
+/// can implement this for their own message types.
+pub trait Wire<'a>: Sized {
+    /// Append the wire encoding of `self` to `out`. Generic over the buffer
+    /// capacity `N` so larger MCUs can opt into bigger frames.
+    fn encode<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ProtoError>;
+    /// Decode a value from the front of `buf`, returning it and the number of
+    /// bytes consumed. The result may borrow from `buf`.
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), ProtoError>;
+}
+
+impl<'a> Wire<'a> for f32 {
+    fn encode<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ProtoError> {
+        out.extend_from_slice(&self.to_bits().to_be_bytes())
+            .map_err(|_| ProtoError::BufferFull)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), ProtoError> {
+        let bytes = buf.get(0..4).ok_or(ProtoError::UnexpectedEof)?;
+        let mut raw = [0_u8; 4];
+        raw.copy_from_slice(bytes);
+        Ok((f32::from_bits(u32::from_be_bytes(raw)), 4))
+    }
+}
+
+impl<'a> Wire<'a> for nalgebra::Vector3<f32> {
+    fn encode<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ProtoError> {
+        self.iter().try_for_each(|el| el.encode(out))
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), ProtoError> {
+        let mut vec = nalgebra::Vector3::<f32>::zeros();
+        let mut consumed = 0;
+        for el in vec.iter_mut() {
+            let (val, n) = f32::decode(buf.get(consumed..).ok_or(ProtoError::UnexpectedEof)?)?;
+            *el = val;
+            consumed += n;
+        }
+        Ok((vec, consumed))
+    }
+}
+
+impl<'a> Wire<'a> for MotorPosition {
+    fn encode<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ProtoError> {
+        out.push((*self).into()).map_err(|_| ProtoError::BufferFull)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), ProtoError> {
+        use core::convert::TryFrom;
+        let id = *buf.get(0).ok_or(ProtoError::UnexpectedEof)?;
+        let motor = MotorPosition::try_from(id).map_err(|_| ProtoError::UnknownMotor(id))?;
+        Ok((motor, 1))
+    }
+}
+
+/// A single telemetry attribute in netlink-style TLV form: `[type][len][payload]`.
+///
+/// New attribute kinds can be added without breaking older receivers: a decoder
+/// that does not recognise a `type` keeps the bytes as [`Attr::Raw`] and advances
+/// by the declared `len`, so forward-compatible senders interoperate with older
+/// firmware.
+#[derive(Clone, Copy, Debug)]
+pub enum Attr<'a> {
+    /// Angular velocity around the three body axes.
+    AngularVel(nalgebra::Vector3<f32>),
+    /// Orientation as a unit quaternion.
+    Orientation(nalgebra::UnitQuaternion<f32>),
+    /// Battery voltage in millivolts.
+    Battery(u16),
+    /// An attribute whose `type` byte is not known to this build.
+    Raw { kind: u8, data: &'a [u8] },
+}
+
+impl<'a> Attr<'a> {
+    const TYPE_ANGULAR_VEL: u8 = 1;
+    const TYPE_ORIENTATION: u8 = 2;
+    const TYPE_BATTERY: u8 = 3;
+
+    /// The TLV `type` byte for this attribute.
+    fn kind(&self) -> u8 {
+        match self {
+            Attr::AngularVel(_) => Self::TYPE_ANGULAR_VEL,
+            Attr::Orientation(_) => Self::TYPE_ORIENTATION,
+            Attr::Battery(_) => Self::TYPE_BATTERY,
+            Attr::Raw { kind, .. } => *kind,
+        }
+    }
+
+    /// Append the `[type][len][payload]` encoding of this attribute to `out`.
+    pub fn encode<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ProtoError> {
+        // Reserve the type and length bytes, then remember where the payload starts
+        // so we can back-fill the real length once it has been written.
+        out.push(self.kind()).map_err(|_| ProtoError::BufferFull)?;
+        let len_idx = out.len();
+        out.push(0).map_err(|_| ProtoError::BufferFull)?;
+        let payload_start = out.len();
+
+        match self {
+            Attr::AngularVel(vel) => vel.encode(out)?,
+            Attr::Orientation(q) => q
+                .as_ref()
+                .coords
+                .iter()
+                .try_for_each(|el| el.encode(out))?,
+            Attr::Battery(mv) => out
+                .extend_from_slice(&mv.to_be_bytes())
+                .map_err(|_| ProtoError::BufferFull)?,
+            Attr::Raw { data, .. } => {
+                out.extend_from_slice(data).map_err(|_| ProtoError::BufferFull)?;
+            }
+        }
+
+        let len = out.len() - payload_start;
+        if len > u8::MAX as usize {
+            return Err(ProtoError::BufferFull);
+        }
+        out[len_idx] = len as u8;
+        Ok(())
+    }
+
+    /// Decode a single attribute from the front of `buf`, returning it and the
+    /// number of bytes consumed. Unknown `type` bytes decode to [`Attr::Raw`].
+    pub fn decode(buf: &'a [u8]) -> Result<(Self, usize), ProtoError> {
+        let kind = *buf.get(0).ok_or(ProtoError::UnexpectedEof)?;
+        let len = *buf.get(1).ok_or(ProtoError::UnexpectedEof)? as usize;
+
+        // Validate the declared length stays inside the buffer before reading.
+        let payload = buf.get(2..2 + len).ok_or(ProtoError::UnexpectedEof)?;
+        let consumed = 2 + len;
+
+        let attr = match kind {
+            Self::TYPE_ANGULAR_VEL => Attr::AngularVel(nalgebra::Vector3::<f32>::decode(payload)?.0),
+            Self::TYPE_ORIENTATION => {
+                // Stored as the raw quaternion coordinates in `[i, j, k, w]` order.
+                let mut coords = [0.0_f32; 4];
+                let mut off = 0;
+                for el in coords.iter_mut() {
+                    let (val, n) =
+                        f32::decode(payload.get(off..).ok_or(ProtoError::UnexpectedEof)?)?;
+                    *el = val;
+                    off += n;
+                }
+                let quat = nalgebra::Quaternion::new(coords[3], coords[0], coords[1], coords[2]);
+                Attr::Orientation(nalgebra::UnitQuaternion::new_unchecked(quat))
+            }
+            Self::TYPE_BATTERY => {
+                let mut raw = [0_u8; 2];
+                raw.copy_from_slice(payload.get(0..2).ok_or(ProtoError::UnexpectedEof)?);
+                Attr::Battery(u16::from_be_bytes(raw))
+            }
+            other => Attr::Raw {
+                kind: other,
+                data: payload,
+            },
+        };
+
+        Ok((attr, consumed))
+    }
+}
+
+/// Capacity of the attribute list carried by [`Command::Telemetry`].
+type MaxAttrs = heapless::consts::U8;
+
+/// Maximum number of samples a single [`Command::ImuDump`] can hold in memory.
+type MaxSamples = U16;
 
 // Definition of the Motor Position
 #[derive(Clone, Copy, Debug)]
@@ -54,136 +324,451 @@ impl core::convert::TryFrom<u8> for MotorPosition {
 }
 
 /// List of possible Commands for serial communication
-#[derive(Clone, Copy, Debug)]
-pub enum Command {
+#[derive(Clone, Debug)]
+pub enum Command<'a> {
     StartMotor(MotorPosition),
     StopMotor(MotorPosition),
     GetMotionState,
     SendMotionState(nalgebra::Vector3<f32>),
     ToggleLed,
+    /// Acknowledgement of a received frame, carrying the sequence number that is being acked.
+    Ack(u8),
+    /// Protocol version handshake. `proto` is the highest version the sender speaks.
+    Hello { proto: u16 },
+    /// Extensible telemetry carrying a list of TLV [`Attr`] attributes.
+    Telemetry(heapless::Vec<Attr<'a>, MaxAttrs>),
+    /// A batched IMU dump of N floats, length-prefixed with a VarInt count.
+    ImuDump(heapless::Vec<f32, MaxSamples>),
 }
 
-impl Command {
-    /// Convert to a byte array representation. Returns Err if for some reason the byte could not
-    /// be pushed to the vector
-    fn to_byte_array(self, out: &mut heapless::Vec<u8, U32>) -> Result<(), ()> {
-        // Clear the Vecotr
-        out.clear();
+impl<'a> Command<'a> {
+    /// The command identifier byte.
+    fn id(&self) -> u8 {
+        use Command::*;
+        match self {
+            ToggleLed => 1,
+            Hello { .. } => 2,
+            StartMotor(_) => 10,
+            StopMotor(_) => 11,
+            GetMotionState => 20,
+            SendMotionState(_) => 21,
+            Telemetry(_) => 22,
+            ImuDump(_) => 23,
+            Ack(_) => 30,
+        }
+    }
+
+    /// Append the wire encoding for protocol version `proto` to `out`, without
+    /// clearing it. Errors if a variant does not exist in the negotiated version
+    /// or the buffer runs full.
+    fn encode_at<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+        proto: u16,
+    ) -> Result<(), ProtoError> {
+        use Command::*;
+
+        // Reject variants that the negotiated protocol version does not know about
+        match self {
+            Ack(_) | Hello { .. } if proto < 2 => {
+                return Err(ProtoError::UnknownCommand(self.id()))
+            }
+            Telemetry(_) | ImuDump(_) if proto < 3 => {
+                return Err(ProtoError::UnknownCommand(self.id()))
+            }
+            _ => (),
+        }
 
         // Add the Cmd Identifyer
-        out.push(self.into()).map_err(|_| ())?;
+        out.push(self.id()).map_err(|_| ProtoError::BufferFull)?;
 
-        use Command::*;
         match self {
             ToggleLed | GetMotionState => (), // No additional Info
-            StartMotor(motor) | StopMotor(motor) => out.push(motor.into()).map_err(|_| ())?, // Just add the Motor Number
-            SendMotionState(angle_vel) => angle_vel.iter().try_for_each(|vel| {
-                vel.to_bits()
-                    .to_be_bytes()
-                    .iter()
-                    .try_for_each(|byte| out.push(*byte).map_err(|_| ()))
-            })?,
+            StartMotor(motor) | StopMotor(motor) => motor.encode(out)?, // Just add the Motor Number
+            SendMotionState(angle_vel) => angle_vel.encode(out)?,
+            Ack(seq) => out.push(*seq).map_err(|_| ProtoError::BufferFull)?, // Append the acked sequence number
+            Hello { proto } => out
+                .extend_from_slice(&proto.to_be_bytes())
+                .map_err(|_| ProtoError::BufferFull)?,
+            Telemetry(attrs) => attrs.iter().try_for_each(|attr| attr.encode(out))?,
+            ImuDump(samples) => {
+                // VarInt element count, then each sample as a big-endian float.
+                write_varint(samples.len() as u32, out)?;
+                samples.iter().try_for_each(|sample| sample.encode(out))?;
+            }
         };
 
         Ok(())
     }
 
-    /// Convert a given array to a Command. Returns Err if length is not correct or an error occured
-    /// while parsing.
-    fn from_byte_array(data: &[u8]) -> Result<Command, ()> {
-        let mut iter = data.iter();
-
+    /// Decode a command from the front of `data` for protocol version `proto`,
+    /// returning the command and the number of bytes consumed.
+    fn decode_at(data: &'a [u8], proto: u16) -> Result<(Command<'a>, usize), ProtoError> {
         use core::convert::TryFrom;
         use Command::*;
 
-        // Decode the command
-        let cmd = iter
-            .next()
-            .ok_or(())
-            .and_then(|val| Command::try_from(*val))?;
-
-        // Try to Decode the payload
-        match cmd {
-            ToggleLed => Ok(ToggleLed),           // No additional Info
-            GetMotionState => Ok(GetMotionState), // No additional Info
-            StartMotor(_) => Ok(StartMotor(
-                iter.next()
-                    .ok_or(())
-                    .and_then(|val| MotorPosition::try_from(*val))?,
-            )),
-            StopMotor(_) => Ok(StopMotor(
-                iter.next()
-                    .ok_or(())
-                    .and_then(|val| MotorPosition::try_from(*val))?,
-            )),
+        // Decode the command id
+        let id = *data.get(0).ok_or(ProtoError::UnexpectedEof)?;
+        let cmd = Command::try_from(id).map_err(|_| ProtoError::UnknownCommand(id))?;
+        let mut consumed = 1;
+
+        // Try to decode the payload
+        let cmd = match cmd {
+            ToggleLed => ToggleLed,           // No additional Info
+            GetMotionState => GetMotionState, // No additional Info
+            StartMotor(_) => {
+                let (motor, n) = MotorPosition::decode(&data[consumed..])?;
+                consumed += n;
+                StartMotor(motor)
+            }
+            StopMotor(_) => {
+                let (motor, n) = MotorPosition::decode(&data[consumed..])?;
+                consumed += n;
+                StopMotor(motor)
+            }
             SendMotionState(_) => {
-                let mut buffer = [0_u8; 4];
-                let mut angle_vel = nalgebra::Vector3::<f32>::zeros();
-                for el in angle_vel.iter_mut() {
-                    // Try to get the next 4 bytes
-                    buffer.iter_mut().try_for_each(|byte| {
-                        *byte = *iter.next().ok_or(())?;
-                        Ok(())
-                    })?;
-                    use core::convert::TryInto;
-                    // Try to convert to a float
-                    *el = f32::from_bits(u32::from_be_bytes(buffer.try_into().map_err(|_| ())?));
+                let (angle_vel, n) = nalgebra::Vector3::<f32>::decode(&data[consumed..])?;
+                consumed += n;
+                SendMotionState(angle_vel)
+            }
+            Telemetry(_) if proto >= 3 => {
+                // The attributes occupy the rest of the frame; SLIP framing delimits it.
+                let mut attrs = heapless::Vec::<Attr, MaxAttrs>::new();
+                while consumed < data.len() {
+                    let (attr, n) = Attr::decode(&data[consumed..])?;
+                    attrs.push(attr).map_err(|_| ProtoError::BufferFull)?;
+                    consumed += n;
                 }
-                Ok(SendMotionState(angle_vel))
+                Telemetry(attrs)
+            }
+            ImuDump(_) if proto >= 3 => {
+                // VarInt element count followed by that many big-endian floats.
+                let mut it = data[consumed..].iter().copied();
+                let count = read_varint(&mut it)?;
+                consumed = data.len() - it.len();
+
+                let mut samples = heapless::Vec::<f32, MaxSamples>::new();
+                for _ in 0..count {
+                    let (sample, n) = f32::decode(&data[consumed..])?;
+                    samples.push(sample).map_err(|_| ProtoError::BufferFull)?;
+                    consumed += n;
+                }
+                ImuDump(samples)
+            }
+            Ack(_) if proto >= 2 => {
+                let seq = *data.get(consumed).ok_or(ProtoError::UnexpectedEof)?;
+                consumed += 1;
+                Ack(seq)
             }
+            Hello { .. } if proto >= 2 => {
+                let bytes = data
+                    .get(consumed..consumed + 2)
+                    .ok_or(ProtoError::UnexpectedEof)?;
+                let mut raw = [0_u8; 2];
+                raw.copy_from_slice(bytes);
+                consumed += 2;
+                Hello {
+                    proto: u16::from_be_bytes(raw),
+                }
+            }
+            // Variant exists in this build but not in the negotiated protocol version.
+            Ack(_) | Hello { .. } | Telemetry(_) | ImuDump(_) => {
+                return Err(ProtoError::UnknownCommand(id))
+            }
+        };
+
+        Ok((cmd, consumed))
+    }
+
+    /// Convert to a byte array representation, encoded for wire protocol `proto`.
+    fn to_byte_array<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+        proto: u16,
+    ) -> Result<(), ProtoError> {
+        // Clear the Vecotr
+        out.clear();
+        self.encode_at(out, proto)
+    }
+
+    /// Convert a given array to a Command, decoded for wire protocol `proto`.
+    /// Errors if bytes remain after a complete command was parsed.
+    fn from_byte_array(data: &'a [u8], proto: u16) -> Result<Command<'a>, ProtoError> {
+        let (cmd, consumed) = Command::decode_at(data, proto)?;
+        if consumed != data.len() {
+            return Err(ProtoError::TrailingBytes);
         }
+        Ok(cmd)
+    }
+
+    /// Convert from a slip coded slice of bytes to a Command, using the newest
+    /// version this build speaks ([`PROTO_VERSION`]).
+    ///
+    /// Use [`Command::from_slip_versioned`] when talking to a peer whose
+    /// negotiated version (see [`negotiate`]) is older.
+    ///
+    /// The decoded bytes are written into `scratch` so that borrowing payloads
+    /// (e.g. [`Attr::Raw`] inside [`Command::Telemetry`]) can reference them for
+    /// as long as the returned command is alive.
+    pub fn from_slip<N: ArrayLength<u8>>(
+        input: &heapless::Vec<u8, N>,
+        scratch: &'a mut heapless::Vec<u8, N>,
+    ) -> Result<Self, ProtoError> {
+        Command::from_slip_versioned(PROTO_VERSION, input, scratch)
     }
 
-    /// Convert from a slip coded slice of bytes to a Command
-    pub fn from_slip(input: &heapless::Vec<u8, U32>) -> Result<Self, ()> {
-        let mut decoded_bytes = heapless::Vec::<u8, U32>::new();
+    /// Like [`Command::from_slip`], but parse for the explicitly supplied
+    /// protocol version so older variants are honored without any global state.
+    pub fn from_slip_versioned<N: ArrayLength<u8>>(
+        proto: u16,
+        input: &heapless::Vec<u8, N>,
+        scratch: &'a mut heapless::Vec<u8, N>,
+    ) -> Result<Self, ProtoError> {
+        scratch.clear();
 
         // Decode Slip
-        rc_framing::framing::decode(input, &mut decoded_bytes)?;
+        rc_framing::framing::decode(input, scratch).map_err(|_| ProtoError::Framing)?;
 
-        // Convert to Command
-        Command::from_byte_array(&decoded_bytes)
+        // Convert to Command for the requested protocol version
+        Command::from_byte_array(scratch, proto)
+    }
+
+    /// Convert a Command to bytes using the newest version this build speaks
+    /// ([`PROTO_VERSION`]). Fails if the target Vector is not long enough.
+    /// Generic over the buffer capacity `N`, so embedded targets keep `U32`
+    /// while larger MCUs can opt into bigger frames.
+    ///
+    /// Use [`Command::to_slip_versioned`] when talking to an older peer.
+    pub fn to_slip<N: ArrayLength<u8>>(
+        &self,
+        output: &mut heapless::Vec<u8, N>,
+    ) -> Result<usize, ProtoError> {
+        self.to_slip_versioned(PROTO_VERSION, output)
     }
 
-    /// Convert a Command to bytes. Fails if the target Vector is not long enough.
-    pub fn to_slip(self, output: &mut heapless::Vec<u8, U32>) -> Result<usize, ()> {
+    /// Like [`Command::to_slip`], but encode for the explicitly supplied
+    /// protocol version so a caller can match each link's negotiated version
+    /// without any global state.
+    pub fn to_slip_versioned<N: ArrayLength<u8>>(
+        &self,
+        proto: u16,
+        output: &mut heapless::Vec<u8, N>,
+    ) -> Result<usize, ProtoError> {
+        let mut bytes = heapless::Vec::<u8, N>::new();
+
+        // Convert to Array for the requested protocol version
+        self.to_byte_array(&mut bytes, proto)?;
+
+        // Encode with SLIP
+        rc_framing::framing::encode(&bytes, output).map_err(|_| ProtoError::Framing)?;
+
+        Ok(output.len())
+    }
+
+    /// Like [`Command::to_slip`], but run the byte array through the AES-CFB8
+    /// `cipher` before SLIP framing. Only available with the `crypto` feature.
+    #[cfg(feature = "crypto")]
+    pub fn to_slip_encrypted(
+        &self,
+        cipher: &mut crypto::Cfb8,
+        output: &mut heapless::Vec<u8, U32>,
+    ) -> Result<usize, ProtoError> {
         let mut bytes = heapless::Vec::<u8, U32>::new();
 
         // Convert to Array
-        self.to_byte_array(&mut bytes)?;
+        self.to_byte_array(&mut bytes, PROTO_VERSION)?;
+
+        // Encrypt in place before framing
+        cipher.encrypt(&mut bytes);
 
         // Encode with SLIP
-        rc_framing::framing::encode(&bytes, output)?;
+        rc_framing::framing::encode(&bytes, output).map_err(|_| ProtoError::Framing)?;
 
         Ok(output.len())
     }
+
+    /// Like [`Command::from_slip`], but decrypt the de-framed bytes with the
+    /// AES-CFB8 `cipher` before parsing. Only available with the `crypto` feature.
+    #[cfg(feature = "crypto")]
+    pub fn from_slip_encrypted(
+        cipher: &mut crypto::Cfb8,
+        input: &heapless::Vec<u8, U32>,
+        scratch: &'a mut heapless::Vec<u8, U32>,
+    ) -> Result<Self, ProtoError> {
+        scratch.clear();
+
+        // Decode Slip
+        rc_framing::framing::decode(input, scratch).map_err(|_| ProtoError::Framing)?;
+
+        // Decrypt in place after framing
+        cipher.decrypt(scratch);
+
+        // Convert to Command
+        Command::from_byte_array(scratch, PROTO_VERSION)
+    }
 }
 
-impl core::convert::TryFrom<u8> for Command {
+impl<'a> Wire<'a> for Command<'a> {
+    fn encode<N: ArrayLength<u8>>(
+        &self,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Result<(), ProtoError> {
+        self.encode_at(out, PROTO_VERSION)
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<(Self, usize), ProtoError> {
+        Command::decode_at(buf, PROTO_VERSION)
+    }
+}
+
+impl<'a> core::convert::TryFrom<u8> for Command<'a> {
     type Error = ();
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         use Command::*;
         match value {
             1 => Ok(ToggleLed),
+            2 => Ok(Hello { proto: 0 }),
             10 => Ok(StartMotor(MotorPosition::All)),
             11 => Ok(StopMotor(MotorPosition::All)),
             20 => Ok(GetMotionState),
             21 => Ok(SendMotionState(nalgebra::Vector3::<f32>::zeros())),
+            22 => Ok(Telemetry(heapless::Vec::new())),
+            23 => Ok(ImuDump(heapless::Vec::new())),
+            30 => Ok(Ack(0)),
             _ => Err(()),
         }
     }
 }
 
-impl core::convert::Into<u8> for Command {
+impl<'a> core::convert::Into<u8> for Command<'a> {
     fn into(self) -> u8 {
-        use Command::*;
-        match self {
-            ToggleLed => 1,
-            StartMotor(_) => 10,
-            StopMotor(_) => 11,
-            GetMotionState => 20,
-            SendMotionState(_) => 21,
+        self.id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::consts::U32;
+
+    #[test]
+    fn v2_variants_rejected_on_v1() {
+        let mut out = heapless::Vec::<u8, U32>::new();
+        assert_eq!(
+            Command::Ack(7).encode_at(&mut out, 1),
+            Err(ProtoError::UnknownCommand(30))
+        );
+        out.clear();
+        assert_eq!(
+            Command::Hello { proto: 2 }.encode_at(&mut out, 1),
+            Err(ProtoError::UnknownCommand(2))
+        );
+    }
+
+    #[test]
+    fn v2_variants_round_trip_on_v2() {
+        let mut out = heapless::Vec::<u8, U32>::new();
+        Command::Hello { proto: 2 }.encode_at(&mut out, 2).unwrap();
+        match Command::from_byte_array(&out, 2).unwrap() {
+            Command::Hello { proto } => assert_eq!(proto, 2),
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn v3_variants_rejected_before_v3() {
+        let mut out = heapless::Vec::<u8, U32>::new();
+        assert_eq!(
+            Command::Telemetry(heapless::Vec::new()).encode_at(&mut out, 2),
+            Err(ProtoError::UnknownCommand(22))
+        );
+        out.clear();
+        Command::Telemetry(heapless::Vec::new())
+            .encode_at(&mut out, 3)
+            .unwrap();
+        assert!(matches!(
+            Command::decode_at(&out, 2),
+            Err(ProtoError::UnknownCommand(22))
+        ));
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for value in [0_u32, 1, 127, 128, 300, 16_384, u32::MAX] {
+            let mut out = heapless::Vec::<u8, U32>::new();
+            write_varint(value, &mut out).unwrap();
+            let mut it = out.iter().copied();
+            assert_eq!(read_varint(&mut it).unwrap(), value);
+            assert_eq!(it.next(), None);
         }
     }
+
+    #[test]
+    fn varint_rejects_noncanonical_fifth_byte() {
+        // A 5th byte with bits above 0x0F would overflow a u32.
+        let bytes = [0xFF_u8, 0xFF, 0xFF, 0xFF, 0x10];
+        let mut it = bytes.iter().copied();
+        assert_eq!(read_varint(&mut it), Err(ProtoError::VarIntOverflow));
+    }
+
+    #[test]
+    fn varint_rejects_overlong_sequence() {
+        // Five continuation bytes push the terminator past the five a u32 allows.
+        let bytes = [0x80_u8, 0x80, 0x80, 0x80, 0x80, 0x01];
+        let mut it = bytes.iter().copied();
+        assert_eq!(read_varint(&mut it), Err(ProtoError::VarIntOverflow));
+    }
+
+    #[test]
+    fn varint_truncated_is_eof() {
+        let bytes = [0x80_u8];
+        let mut it = bytes.iter().copied();
+        assert_eq!(read_varint(&mut it), Err(ProtoError::UnexpectedEof));
+    }
+
+    #[test]
+    fn attr_battery_round_trip() {
+        let mut out = heapless::Vec::<u8, U32>::new();
+        Attr::Battery(11500).encode(&mut out).unwrap();
+        let (attr, consumed) = Attr::decode(&out).unwrap();
+        assert_eq!(consumed, out.len());
+        match attr {
+            Attr::Battery(mv) => assert_eq!(mv, 11500),
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn attr_unknown_type_kept_as_raw_and_skipped_by_len() {
+        // [type=0x55][len=3][payload...][trailing sentinel]
+        let buf = [0x55, 3, 0xAA, 0xBB, 0xCC, 0x99];
+        let (attr, consumed) = Attr::decode(&buf).unwrap();
+        assert_eq!(consumed, 5);
+        match attr {
+            Attr::Raw { kind, data } => {
+                assert_eq!(kind, 0x55);
+                assert_eq!(data, &[0xAA, 0xBB, 0xCC]);
+            }
+            other => panic!("unexpected: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn attr_len_past_buffer_is_rejected() {
+        // Declares 8 payload bytes but only 2 are present.
+        let buf = [0x03, 8, 0x00, 0x01];
+        assert!(matches!(Attr::decode(&buf), Err(ProtoError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn v1_peer_cannot_decode_v2_frame() {
+        // A v2 Ack frame decoded as v1 must not silently parse as a known command.
+        let mut out = heapless::Vec::<u8, U32>::new();
+        Command::Ack(3).encode_at(&mut out, 2).unwrap();
+        assert!(matches!(
+            Command::decode_at(&out, 1),
+            Err(ProtoError::UnknownCommand(30))
+        ));
+    }
 }
@@ -0,0 +1,267 @@
+//! Reliable session layer on top of the bare [`Command`] protocol.
+//!
+//! `to_slip`/`from_slip` on [`Command`] are fire-and-forget: a frame that gets
+//! mangled on a noisy serial link is simply lost. The [`Session`] here prefixes
+//! every outgoing command with an 8-bit sequence number, keeps the unacked
+//! frames around in a fixed size map and retransmits them until the peer echoes
+//! back a matching [`Command::Ack`]. Everything stays `no_std` and allocation
+//! free.
+
+use heapless::consts::{U16, U32};
+use heapless::{FnvIndexMap, Vec};
+
+use crate::{Command, ProtoError, PROTO_VERSION};
+
+/// Number of frames that may be in flight (unacked) at the same time. Must be a
+/// power of two for the [`FnvIndexMap`].
+type Pending = U16;
+
+/// A single outstanding frame together with the last time it was put on the
+/// wire, used to decide when a retransmission is due.
+#[derive(Clone)]
+struct Entry<'a> {
+    cmd: Command<'a>,
+    last_sent_ms: u32,
+}
+
+/// Reliable, sequence numbered session around the [`Command`] protocol.
+pub struct Session<'a> {
+    /// Sequence number handed out to the next frame.
+    next_seq: u8,
+    /// Retransmission timeout in milliseconds.
+    timeout_ms: u32,
+    /// Latest timestamp the session has seen, fed in through [`Session::poll_retransmit`].
+    now_ms: u32,
+    /// Wire protocol version this session encodes and decodes with.
+    proto: u16,
+    /// Frames still waiting for their ACK, keyed by sequence number.
+    pending: FnvIndexMap<u8, Entry<'a>, Pending>,
+    /// Highest data-frame sequence number delivered so far, used as a wrapping
+    /// high-water mark so a retransmit whose ACK was lost is re-ACKed but never
+    /// delivered to the application twice.
+    recv_hwm: Option<u8>,
+}
+
+impl<'a> Session<'a> {
+    /// Create a new session that retransmits a frame if no ACK arrives within
+    /// `timeout_ms` milliseconds.
+    pub fn new(timeout_ms: u32) -> Self {
+        Session {
+            next_seq: 0,
+            timeout_ms,
+            now_ms: 0,
+            proto: PROTO_VERSION,
+            pending: FnvIndexMap::new(),
+            recv_hwm: None,
+        }
+    }
+
+    /// Whether `seq` is newer than the high-water mark, treating the 8-bit
+    /// sequence space as a ring: a frame within the forward half ahead of the
+    /// mark is new, a frame on or behind it is a duplicate.
+    fn is_new_seq(&self, seq: u8) -> bool {
+        match self.recv_hwm {
+            None => true,
+            Some(hwm) => {
+                let ahead = seq.wrapping_sub(hwm);
+                ahead != 0 && ahead < 128
+            }
+        }
+    }
+
+    /// Pin the session to the protocol version that came out of [`crate::negotiate`],
+    /// so subsequent frames are encoded and decoded for a version both ends share.
+    pub fn set_proto(&mut self, proto: u16) {
+        self.proto = proto;
+    }
+
+    /// Hand out the next sequence number, wrapping around at 255.
+    fn alloc_seq(&mut self) -> u8 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        seq
+    }
+
+    /// SLIP encode `[seq][command bytes]` into `out` for protocol version `proto`.
+    fn encode_frame(
+        seq: u8,
+        cmd: &Command,
+        proto: u16,
+        out: &mut Vec<u8, U32>,
+    ) -> Result<(), ProtoError> {
+        let mut bytes = Vec::<u8, U32>::new();
+
+        // Sequence number is the session header byte
+        bytes.push(seq).map_err(|_| ProtoError::BufferFull)?;
+
+        // Followed by the plain command encoding
+        let mut cmd_bytes = Vec::<u8, U32>::new();
+        cmd.to_byte_array(&mut cmd_bytes, proto)?;
+        bytes
+            .extend_from_slice(&cmd_bytes)
+            .map_err(|_| ProtoError::BufferFull)?;
+
+        rc_framing::framing::encode(&bytes, out).map_err(|_| ProtoError::Framing)?;
+        Ok(())
+    }
+
+    /// Serialize `cmd` into `out` as a sequenced, SLIP encoded frame and record
+    /// it as outstanding until the matching ACK is seen. Returns the sequence
+    /// number assigned to the frame.
+    pub fn send(&mut self, cmd: Command<'a>, out: &mut Vec<u8, U32>) -> Result<u8, ProtoError> {
+        let seq = self.alloc_seq();
+        Self::encode_frame(seq, &cmd, self.proto, out)?;
+
+        let entry = Entry {
+            cmd,
+            last_sent_ms: self.now_ms,
+        };
+        self.pending
+            .insert(seq, entry)
+            .map_err(|_| ProtoError::BufferFull)?;
+
+        Ok(seq)
+    }
+
+    /// Feed a received SLIP frame into the session.
+    ///
+    /// A data frame clears to `Some((seq, cmd))` and an automatic
+    /// [`Command::Ack`] for it is encoded into `ack_out`. An incoming ACK clears
+    /// the corresponding pending entry and yields `None`, as does any frame that
+    /// fails to decode. A data frame whose sequence number is not newer than the
+    /// high-water mark (because our ACK was lost and the peer retransmitted) is
+    /// re-ACKed but yields `None`, so the application sees each in-order frame at
+    /// most once across the full 8-bit sequence space. The decoded bytes are
+    /// written into `scratch` so that borrowing payloads can reference them for
+    /// as long as the returned command is alive.
+    pub fn on_receive<'b>(
+        &mut self,
+        bytes: &Vec<u8, U32>,
+        scratch: &'b mut Vec<u8, U32>,
+        ack_out: &mut Vec<u8, U32>,
+    ) -> Option<(u8, Command<'b>)> {
+        scratch.clear();
+        rc_framing::framing::decode(bytes, scratch).ok()?;
+
+        let seq = *scratch.get(0)?;
+
+        // Decode the command that follows the sequence header; a borrowing
+        // payload in the returned value references `scratch`.
+        let cmd = Command::from_byte_array(scratch.get(1..)?, self.proto).ok()?;
+
+        match cmd {
+            Command::Ack(acked) => {
+                self.pending.remove(&acked);
+                None
+            }
+            other => {
+                // Acknowledge the data frame automatically, even on a duplicate,
+                // so the peer stops retransmitting after a lost ACK.
+                let ack_seq = self.alloc_seq();
+                let ack = Command::Ack(seq);
+                let _ = Self::encode_frame(ack_seq, &ack, self.proto, ack_out);
+
+                // Suppress a frame we have already delivered, advancing the
+                // high-water mark only for genuinely new sequence numbers.
+                if !self.is_new_seq(seq) {
+                    return None;
+                }
+                self.recv_hwm = Some(seq);
+
+                Some((seq, other))
+            }
+        }
+    }
+
+    /// Re-emit every frame whose ACK has not arrived within the configured
+    /// timeout, resetting its retransmission clock to `now_ms`.
+    ///
+    /// Each item is a SLIP encoded frame carrying the frame's *original*
+    /// sequence number, so the peer's ACK clears the existing pending entry
+    /// instead of the retransmit allocating a fresh seq that never matches.
+    pub fn poll_retransmit(&mut self, now_ms: u32) -> impl Iterator<Item = Vec<u8, U32>> {
+        self.now_ms = now_ms;
+
+        let proto = self.proto;
+        let mut due = Vec::<Vec<u8, U32>, Pending>::new();
+        for (seq, entry) in self.pending.iter_mut() {
+            if now_ms.wrapping_sub(entry.last_sent_ms) >= self.timeout_ms {
+                entry.last_sent_ms = now_ms;
+                let mut frame = Vec::<u8, U32>::new();
+                if Self::encode_frame(*seq, &entry.cmd, proto, &mut frame).is_ok() {
+                    let _ = due.push(frame);
+                }
+            }
+        }
+
+        due.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    extern crate std;
+
+    /// SLIP decode a frame and return `(seq, command bytes)`.
+    fn unframe(frame: &Vec<u8, U32>) -> (u8, Vec<u8, U32>) {
+        let mut decoded = Vec::<u8, U32>::new();
+        rc_framing::framing::decode(frame, &mut decoded).unwrap();
+        let seq = decoded[0];
+        let mut rest = Vec::<u8, U32>::new();
+        rest.extend_from_slice(&decoded[1..]).unwrap();
+        (seq, rest)
+    }
+
+    #[test]
+    fn ack_clears_pending_entry() {
+        let mut s = Session::new(100);
+        let mut out = Vec::<u8, U32>::new();
+        let seq = s.send(Command::ToggleLed, &mut out).unwrap();
+        assert_eq!(s.pending.len(), 1);
+
+        // A peer echoes back the ACK for `seq`.
+        let mut ack = Vec::<u8, U32>::new();
+        Session::encode_frame(0, &Command::Ack(seq), PROTO_VERSION, &mut ack).unwrap();
+
+        let mut scratch = Vec::<u8, U32>::new();
+        let mut ack_out = Vec::<u8, U32>::new();
+        assert!(s.on_receive(&ack, &mut scratch, &mut ack_out).is_none());
+        assert_eq!(s.pending.len(), 0);
+    }
+
+    #[test]
+    fn retransmit_reuses_original_seq_after_timeout() {
+        let mut s = Session::new(100);
+        let mut out = Vec::<u8, U32>::new();
+        let seq = s.send(Command::ToggleLed, &mut out).unwrap();
+
+        // Nothing is due before the timeout elapses.
+        assert_eq!(s.poll_retransmit(50).count(), 0);
+
+        // Once it does, the frame is re-emitted under its original sequence number.
+        let frames: std::vec::Vec<Vec<u8, U32>> = s.poll_retransmit(100).collect();
+        assert_eq!(frames.len(), 1);
+        let (resent_seq, _) = unframe(&frames[0]);
+        assert_eq!(resent_seq, seq);
+    }
+
+    #[test]
+    fn duplicate_data_frame_is_reacked_but_delivered_once() {
+        let mut s = Session::new(100);
+
+        // A peer sends a data frame with sequence number 5.
+        let mut frame = Vec::<u8, U32>::new();
+        Session::encode_frame(5, &Command::ToggleLed, PROTO_VERSION, &mut frame).unwrap();
+
+        let mut scratch = Vec::<u8, U32>::new();
+        let mut ack1 = Vec::<u8, U32>::new();
+        assert!(s.on_receive(&frame, &mut scratch, &mut ack1).is_some());
+        assert!(!ack1.is_empty());
+
+        // The peer retransmits the same frame: re-ACKed, but not delivered again.
+        let mut ack2 = Vec::<u8, U32>::new();
+        assert!(s.on_receive(&frame, &mut scratch, &mut ack2).is_none());
+        assert!(!ack2.is_empty());
+    }
+}